@@ -7,10 +7,11 @@ use pulldown_cmark::{BrokenLink, CodeBlockKind, CowStr, Event, Tag};
 use std::{
     ffi::OsStr,
     fs::File,
-    io::{self, stdout},
-    path::PathBuf,
+    io::{self, stdout, BufRead as _, Write as _},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
 };
-use tracing::{debug, warn};
+use tracing::{debug, error, warn};
 
 #[derive(Debug, clap::Parser)]
 #[command(name = "cargo", bin_name = "cargo")]
@@ -32,9 +33,42 @@ struct Args {
     #[arg(short, long, default_value = "nightly")]
     toolchain: String,
 
-    /// File to write to
+    /// Document private items, as if `--document-private-items` was passed
+    /// to `cargo doc`.
+    #[arg(long)]
+    document_private_items: bool,
+
+    /// Build for the given target triple.
+    #[arg(long)]
+    target: Option<String>,
+
+    /// Cap lints at the given level (e.g `warn`) while building the
+    /// rustdoc JSON, so crates with warnings (or worse) still produce docs.
+    #[arg(long)]
+    cap_lints: Option<String>,
+
+    /// Base URL for resolved intra-doc links: every link into the crate being
+    /// documented is built on it, as is every link into an external crate
+    /// that doesn't provide its own `html_root_url`.
+    #[arg(long, default_value = "https://docs.rs")]
+    link_base: String,
+
+    /// File to write to.
+    ///
+    /// When extracting more than one package's README, this may be a
+    /// directory, or a template containing `{name}` (e.g.
+    /// `docs/{name}/README.md`), which is substituted with each package's
+    /// name. If omitted and more than one package is selected, a
+    /// `README.md` is written next to each package's `Cargo.toml`.
     #[arg(short, long)]
     output: Option<PathBuf>,
+
+    /// Don't write anything. Instead, compare the generated README against
+    /// the file it would otherwise be written to (see `--output`; stdout is
+    /// an error), and exit non-zero if they differ. Useful in
+    /// CI to assert that a committed README is still in sync with the docs.
+    #[arg(long)]
+    check: bool,
 }
 
 #[test]
@@ -49,7 +83,12 @@ fn main() -> color_eyre::Result<()> {
         workspace,
         features,
         toolchain,
+        document_private_items,
+        target,
+        cap_lints,
+        link_base,
         output,
+        check,
     } = get_args_and_setup_logging()?;
 
     let mut metadata = manifest.metadata();
@@ -67,6 +106,11 @@ fn main() -> color_eyre::Result<()> {
         debug!(?selected, excluded = excluded.len(), "packages")
     }
 
+    if selected.is_empty() {
+        bail!("no packages selected")
+    }
+    let multiple = selected.len() > 1;
+
     let Features {
         all_features,
         no_default_features,
@@ -74,84 +118,681 @@ fn main() -> color_eyre::Result<()> {
         ..
     } = features;
 
-    let output: Box<dyn io::Write> = match output {
-        Some(path) if path == OsStr::new("-") => Box::new(stdout()),
-        None => Box::new(stdout()),
-        Some(path) => Box::new(File::create(path).context("couldn't open output file")?),
+    let cache_dir = metadata.target_directory.join("cargo-extract-readme");
+    let mut out_of_sync = false;
+
+    for package in selected {
+        let output_path = resolve_output_path(output.as_deref(), package, multiple);
+
+        let fingerprint = fingerprint(
+            package,
+            all_features,
+            no_default_features,
+            &features,
+            &toolchain,
+            target.as_deref(),
+            document_private_items,
+            cap_lints.as_deref(),
+            &link_base,
+            output_path.as_deref(),
+        )
+        .with_context(|| format!("couldn't fingerprint sources for {}", package.name))?;
+        let cache_path = cache_dir.join(format!("{fingerprint:016x}.md"));
+
+        let rendered = match std::fs::read_to_string(&cache_path) {
+            Ok(cached) => {
+                debug!(package = %package.name, cache = %cache_path, "cache hit, skipping rustdoc-json build");
+                Some(cached)
+            }
+            Err(_) => {
+                let json_path = build_rustdoc_json(
+                    &manifest,
+                    package,
+                    &toolchain,
+                    all_features,
+                    no_default_features,
+                    &features,
+                    target.as_deref(),
+                    document_private_items,
+                    cap_lints.as_deref(),
+                    &metadata.target_directory,
+                )
+                .with_context(|| format!("couldn't build rustdoc json for {}", package.name))?;
+
+                match json_path {
+                    None => None,
+                    Some(json_path) => {
+                        let krate = serde_json::from_reader::<_, rustdoc_types::Crate>(
+                            File::open(json_path)
+                                .context("couldn't open file containing rustdoc-json")?,
+                        )
+                        .context("couldn't deserialize rustdoc json")?;
+
+                        match &krate.index[&krate.root].docs {
+                            None => {
+                                warn!(package = %package.name, "root does not have any documentation, skipping");
+                                None
+                            }
+                            Some(root_docs) => {
+                                let rendered = render_readme(root_docs, &krate, &link_base, package)?;
+                                std::fs::create_dir_all(&cache_dir)
+                                    .context("couldn't create rustdoc-json cache directory")?;
+                                std::fs::write(&cache_path, &rendered)
+                                    .context("couldn't write readme cache")?;
+                                Some(rendered)
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        let Some(rendered) = rendered else { continue };
+
+        if check {
+            let existing = match &output_path {
+                Some(path) => std::fs::read_to_string(path).unwrap_or_default(),
+                None => bail!("--check requires --output"),
+            };
+            if existing != rendered {
+                warn!(package = %package.name, "README is out of date");
+                log_diff(&existing, &rendered);
+                out_of_sync = true;
+            }
+            continue;
+        }
+
+        if let Some(parent) = output_path.as_deref().and_then(Path::parent) {
+            std::fs::create_dir_all(parent).context("couldn't create output directory")?;
+        }
+        let mut output: Box<dyn io::Write> = match &output_path {
+            Some(path) => Box::new(
+                File::create(path)
+                    .with_context(|| format!("couldn't open output file {}", path.display()))?,
+            ),
+            None => Box::new(stdout()),
+        };
+        output
+            .write_all(rendered.as_bytes())
+            .with_context(|| format!("couldn't write output for {}", package.name))?;
+    }
+
+    if out_of_sync {
+        bail!("one or more READMEs are out of sync with their crate's docs")
+    }
+
+    Ok(())
+}
+
+/// Work out where the README for a given package should be written.
+///
+/// `None` means stdout. A `--output` of `-` always means stdout. Otherwise,
+/// a path containing `{name}` is templated per-package, an existing
+/// directory has `<name>/README.md` appended, and anything else is used
+/// verbatim (only sensible when a single package is selected). With no
+/// `--output` at all, a README is written next to each selected package's
+/// `Cargo.toml` once more than one package is selected.
+fn resolve_output_path(
+    output: Option<&Path>,
+    package: &cargo_metadata::Package,
+    multiple: bool,
+) -> Option<PathBuf> {
+    match output {
+        Some(path) if path == OsStr::new("-") => None,
+        Some(path) if path.to_string_lossy().contains("{name}") => Some(PathBuf::from(
+            path.to_string_lossy().replace("{name}", &package.name),
+        )),
+        Some(path) if path.is_dir() => Some(path.join(&package.name).join("README.md")),
+        Some(path) => Some(path.to_path_buf()),
+        None if multiple => Some(package.manifest_path.as_std_path().with_file_name("README.md")),
+        None => None,
+    }
+}
+
+/// Run `cargo rustdoc` ourselves (rather than through `rustdoc_json::Builder`)
+/// so we can stream its `--message-format=json` output, forwarding any
+/// compiler diagnostics to `tracing` as they arrive, and return the path to
+/// the rustdoc JSON it produced.
+///
+/// Returns `Ok(None)` when the package has nothing documentable (no
+/// lib/rlib/proc-macro target), so the caller can skip just this package
+/// rather than aborting the whole run.
+#[allow(clippy::too_many_arguments)]
+fn build_rustdoc_json(
+    manifest: &Manifest,
+    package: &cargo_metadata::Package,
+    toolchain: &str,
+    all_features: bool,
+    no_default_features: bool,
+    features: &[String],
+    target: Option<&str>,
+    document_private_items: bool,
+    cap_lints: Option<&str>,
+    target_directory: &cargo_metadata::camino::Utf8Path,
+) -> color_eyre::Result<Option<PathBuf>> {
+    let Some(lib_target) = package.targets.iter().find(|target| {
+        target
+            .kind
+            .iter()
+            .any(|kind| kind == "lib" || kind == "rlib" || kind == "proc-macro")
+    }) else {
+        warn!(package = %package.name, "no lib target to document, skipping");
+        return Ok(None);
     };
 
-    let mut json_builder = rustdoc_json::Builder::default();
-    if let Some(path) = manifest.manifest_path {
-        json_builder = json_builder.manifest_path(path)
+    let mut cmd = Command::new("cargo");
+    cmd.arg(format!("+{toolchain}"))
+        .arg("rustdoc")
+        .arg("--package")
+        .arg(&package.name)
+        .arg("--message-format=json");
+    if let Some(manifest_path) = &manifest.manifest_path {
+        cmd.arg("--manifest-path").arg(manifest_path);
+    }
+    if all_features {
+        cmd.arg("--all-features");
+    }
+    if no_default_features {
+        cmd.arg("--no-default-features");
+    }
+    if !features.is_empty() {
+        cmd.arg("--features").arg(features.join(","));
     }
-    for package in &workspace.package {
-        json_builder = json_builder.package(package)
+    if let Some(target) = target {
+        cmd.arg("--target").arg(target);
+    }
+    cmd.arg("--")
+        .arg("-Z")
+        .arg("unstable-options")
+        .arg("--output-format")
+        .arg("json");
+    if document_private_items {
+        cmd.arg("--document-private-items");
+    }
+    if let Some(level) = cap_lints {
+        cmd.arg(format!("--cap-lints={level}"));
     }
 
-    let json_path = json_builder
-        .all_features(all_features)
-        .no_default_features(no_default_features)
-        .features(features)
-        .toolchain(toolchain)
-        .build()?;
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("couldn't spawn cargo rustdoc")?;
+    let stdout = child.stdout.take().expect("stdout was piped");
 
-    let krate = serde_json::from_reader::<_, rustdoc_types::Crate>(
-        File::open(json_path).context("couldn't open file containing rustdoc-json")?,
-    )
-    .context("couldn't deserialize rustdoc json")?;
+    for line in io::BufReader::new(stdout).lines() {
+        let line = line.context("couldn't read cargo rustdoc output")?;
+        match serde_json::from_str::<cargo_metadata::Message>(&line) {
+            Ok(cargo_metadata::Message::CompilerMessage(msg)) => forward_diagnostic(&msg),
+            Ok(_) => (),
+            Err(error) => debug!(%error, line, "couldn't parse cargo message, skipping"),
+        }
+    }
 
-    let Some(root_docs) = &krate.index[&krate.root].docs else {
-        bail!("root does not have any documentation")
+    let status = child
+        .wait()
+        .context("couldn't wait for cargo rustdoc to finish")?;
+    if !status.success() {
+        bail!("cargo rustdoc exited with {status}")
+    }
+
+    let doc_dir = match target {
+        Some(triple) => target_directory.join(triple).join("doc"),
+        None => target_directory.join("doc"),
     };
+    Ok(Some(
+        doc_dir
+            .join(format!("{}.json", lib_target.name.replace('-', "_")))
+            .into_std_path_buf(),
+    ))
+}
+
+/// Forward a single compiler diagnostic from a `cargo rustdoc
+/// --message-format=json` stream into `tracing`, at a level matching its
+/// own.
+fn forward_diagnostic(msg: &cargo_metadata::CompilerMessage) {
+    let rendered = msg
+        .message
+        .rendered
+        .as_deref()
+        .unwrap_or(&msg.message.message);
+    use cargo_metadata::diagnostic::DiagnosticLevel;
+    match msg.message.level {
+        DiagnosticLevel::Ice | DiagnosticLevel::Error => error!("{rendered}"),
+        DiagnosticLevel::Warning => warn!("{rendered}"),
+        _ => debug!("{rendered}"),
+    }
+}
+
+/// Render a package's root docs into a README, resolving intra-doc links
+/// and cleaning up doctest code blocks along the way.
+fn render_readme(
+    root_docs: &str,
+    krate: &rustdoc_types::Crate,
+    link_base: &str,
+    package: &cargo_metadata::Package,
+) -> color_eyre::Result<String> {
+    let mut buf = String::new();
     let mut state = pulldown_cmark_to_cmark::State::default();
+    // Whether we're currently inside a doctest-able rust code block, i.e.
+    // an indented block or a fenced one that `is_rust_fence` accepts.
+    let mut in_rust_fence = false;
 
-    fmt2io::write(output, |output| {
-        for mut event in pulldown_cmark::Parser::new_with_broken_link_callback(
-            root_docs,
-            pulldown_cmark::Options::empty(),
-            Some(&mut |BrokenLink {
-                           span,
-                           link_type,
-                           reference,
-                       }| {
-                warn!(?span, ?link_type, ?reference, "broken_link");
-                None
-            }),
-        ) {
-            debug!(?event);
-            match &event {
-                Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(hint)))
-                    if hint.as_ref() == "" =>
-                {
+    for mut event in pulldown_cmark::Parser::new_with_broken_link_callback(
+        root_docs,
+        pulldown_cmark::Options::empty(),
+        Some(&mut |BrokenLink {
+                       span,
+                       link_type,
+                       reference,
+                   }| {
+            match resolve_intra_doc_link(&reference, krate, link_base, package) {
+                Some((url, title)) => Some((url.into(), title.into())),
+                None => {
+                    warn!(?span, ?link_type, ?reference, "broken_link");
+                    None
+                }
+            }
+        }),
+    ) {
+        debug!(?event);
+        match &event {
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Indented)) => {
+                in_rust_fence = true;
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(hint))) => {
+                in_rust_fence = is_rust_fence(hint);
+                if in_rust_fence {
                     event = Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(CowStr::Borrowed(
                         "rust",
                     ))))
                 }
-                Event::Text(code_block) if state.is_in_code_block => {
-                    let stripped = code_block
-                        .lines()
-                        .filter(|line| !line.starts_with("# "))
-                        .join("\n")
-                        .into_boxed_str();
-                    event = Event::Text(CowStr::Boxed(stripped))
-                }
-                _ => (),
             }
+            Event::End(Tag::CodeBlock(_)) => {
+                in_rust_fence = false;
+            }
+            Event::Text(code_block) if in_rust_fence => {
+                let stripped = code_block
+                    .lines()
+                    .filter_map(strip_hidden_doctest_line)
+                    .join("\n")
+                    .into_boxed_str();
+                event = Event::Text(CowStr::Boxed(stripped))
+            }
+            _ => (),
+        }
+
+        state =
+            pulldown_cmark_to_cmark::cmark_resume(std::iter::once(event), &mut buf, Some(state))?;
+    }
+    state.finalize(&mut buf)?;
+    Ok(buf)
+}
+
+/// Compute a cache key for a package's rendered README from the builder
+/// inputs that can affect it, plus the mtimes of its source files, mirroring
+/// cargo's own fingerprinting: if none of these change, re-running the
+/// (expensive) rustdoc-json build would produce the same output.
+#[allow(clippy::too_many_arguments)]
+fn fingerprint(
+    package: &cargo_metadata::Package,
+    all_features: bool,
+    no_default_features: bool,
+    features: &[String],
+    toolchain: &str,
+    target: Option<&str>,
+    document_private_items: bool,
+    cap_lints: Option<&str>,
+    link_base: &str,
+    output_path: Option<&Path>,
+) -> color_eyre::Result<u64> {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    package.name.hash(&mut hasher);
+    package.version.to_string().hash(&mut hasher);
+    all_features.hash(&mut hasher);
+    no_default_features.hash(&mut hasher);
+    let mut features = features.to_vec();
+    features.sort();
+    features.hash(&mut hasher);
+    toolchain.hash(&mut hasher);
+    target.hash(&mut hasher);
+    document_private_items.hash(&mut hasher);
+    cap_lints.hash(&mut hasher);
+    // Affects the rendered output (resolved link URLs), so a run with a
+    // different `--link-base` must not reuse another run's cache entry.
+    link_base.hash(&mut hasher);
+    // Affects the rendering logic itself, so upgrading to a build with
+    // different rendering behaviour invalidates any cache it left behind.
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    hash_package_sources(package, output_path, &mut hasher)?;
+    Ok(hasher.finish())
+}
 
-            state = pulldown_cmark_to_cmark::cmark_resume(
-                std::iter::once(event),
-                &mut *output,
-                Some(state),
-            )?;
+/// Hash the relative path and mtime of every file under a package's root
+/// (skipping `target/` and `.git/` directories, and the README we're about to
+/// write, so writing it doesn't invalidate the cache entry we just wrote),
+/// so the fingerprint changes whenever a source or doc comment is edited,
+/// even if nothing in `Cargo.toml` did.
+fn hash_package_sources(
+    package: &cargo_metadata::Package,
+    output_path: Option<&Path>,
+    hasher: &mut impl std::hash::Hasher,
+) -> color_eyre::Result<()> {
+    use std::hash::Hash;
+
+    let root = package
+        .manifest_path
+        .parent()
+        .with_context(|| format!("{} has no manifest directory", package.name))?
+        .as_std_path()
+        .to_path_buf();
+    // The walked entries are absolute, but `--output` may be relative to the
+    // current directory.
+    let output_path = match output_path {
+        Some(path) => Some(
+            std::env::current_dir()
+                .context("couldn't get current directory")?
+                .join(path),
+        ),
+        None => None,
+    };
+
+    let mut files = Vec::new();
+    let mut dirs = vec![root];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)
+            .with_context(|| format!("couldn't read directory {}", dir.display()))?
+        {
+            let entry = entry.context("couldn't read directory entry")?;
+            match entry.file_name().to_str() {
+                Some("target" | ".git") => continue,
+                _ => (),
+            }
+            let file_type = entry.file_type().context("couldn't stat directory entry")?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if output_path
+                .as_deref()
+                .is_some_and(|output_path| output_path == entry.path())
+            {
+                continue;
+            } else {
+                let modified = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .with_context(|| format!("couldn't read mtime of {}", entry.path().display()))?;
+                files.push((entry.path(), modified));
+            }
         }
-        state.finalize(output)?;
-        Ok(())
-    })
-    .context("couldn't write output")?;
+    }
+    files.sort();
 
+    for (path, modified) in files {
+        path.hash(hasher);
+        modified.hash(hasher);
+    }
     Ok(())
 }
 
+/// Log a line-oriented diff between the existing and freshly rendered
+/// README, for `--check` to explain why it's failing.
+fn log_diff(existing: &str, rendered: &str) {
+    for line in existing.lines().zip_longest(rendered.lines()) {
+        match line {
+            itertools::EitherOrBoth::Both(old, new) if old == new => (),
+            itertools::EitherOrBoth::Both(old, new) => {
+                warn!("- {old}");
+                warn!("+ {new}");
+            }
+            itertools::EitherOrBoth::Left(old) => warn!("- {old}"),
+            itertools::EitherOrBoth::Right(new) => warn!("+ {new}"),
+        }
+    }
+}
+
+/// Whether a fenced code block's info string marks it as a rust doctest: the
+/// info string is empty, one of its comma-separated segments is `rust`, or
+/// every segment is a known doctest-only attribute (`no_run`, `ignore`,
+/// `should_panic`, `compile_fail`, `edition20xx`) with no other language
+/// named.
+fn is_rust_fence(info_string: &str) -> bool {
+    const DOCTEST_ATTRS: &[&str] = &["no_run", "ignore", "should_panic", "compile_fail"];
+
+    let segments = info_string
+        .split(',')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    segments.is_empty()
+        || segments.iter().any(|segment| *segment == "rust")
+        || segments
+            .iter()
+            .all(|segment| DOCTEST_ATTRS.contains(segment) || segment.starts_with("edition20"))
+}
+
+#[test]
+fn is_rust_fence_cases() {
+    assert!(is_rust_fence(""));
+    assert!(is_rust_fence("rust"));
+    assert!(is_rust_fence("rust,no_run"));
+    assert!(is_rust_fence("no_run"));
+    assert!(is_rust_fence("ignore"));
+    assert!(is_rust_fence("should_panic"));
+    assert!(is_rust_fence("compile_fail"));
+    assert!(is_rust_fence("edition2021"));
+    assert!(is_rust_fence("no_run,ignore"));
+    assert!(!is_rust_fence("toml"));
+    assert!(!is_rust_fence("console"));
+    assert!(!is_rust_fence("ignore,python"));
+}
+
+/// Apply rustdoc's hidden-line rules for a single line of a rust doctest.
+///
+/// A line is hidden (returns `None`) when its first non-whitespace
+/// characters are `#` followed by a space or the end of the line. A line
+/// beginning with `##` is an escaped `#` and is emitted with exactly one
+/// leading `#` removed. Everything else is passed through unchanged.
+fn strip_hidden_doctest_line(line: &str) -> Option<String> {
+    let trimmed = line.trim_start();
+    let indent = &line[..line.len() - trimmed.len()];
+    if trimmed == "#" || trimmed.starts_with("# ") {
+        None
+    } else if let Some(unescaped) = trimmed.strip_prefix('#').filter(|s| s.starts_with('#')) {
+        Some(format!("{indent}{unescaped}"))
+    } else {
+        Some(line.to_owned())
+    }
+}
+
+#[test]
+fn strip_hidden_doctest_line_cases() {
+    assert_eq!(
+        strip_hidden_doctest_line("let x = 1;"),
+        Some("let x = 1;".to_owned())
+    );
+    assert_eq!(
+        strip_hidden_doctest_line("#[derive(Debug)]"),
+        Some("#[derive(Debug)]".to_owned())
+    );
+    assert_eq!(strip_hidden_doctest_line("# let x = 1;"), None);
+    assert_eq!(strip_hidden_doctest_line("#"), None);
+    assert_eq!(strip_hidden_doctest_line("   # indented hidden"), None);
+    assert_eq!(
+        strip_hidden_doctest_line("## still shown"),
+        Some("# still shown".to_owned())
+    );
+}
+
+/// Strip the decoration rustdoc allows around an intra-doc link reference:
+/// surrounding backticks, a disambiguator prefix (`` struct@ ``, `` fn@ ``,
+/// ...), and a trailing `()` marking a function/macro call.
+fn normalize_reference(reference: &str) -> &str {
+    const DISAMBIGUATORS: &[&str] = &[
+        "struct@", "enum@", "trait@", "fn@", "macro@", "type@", "const@", "constant@", "mod@",
+        "union@", "derive@", "value@", "primitive@",
+    ];
+
+    let raw = reference.trim().trim_matches('`');
+    let raw = raw.strip_suffix("()").unwrap_or(raw);
+    DISAMBIGUATORS
+        .iter()
+        .find_map(|prefix| raw.strip_prefix(prefix))
+        .unwrap_or(raw)
+}
+
+#[test]
+fn normalize_reference_cases() {
+    assert_eq!(normalize_reference("Foo"), "Foo");
+    assert_eq!(normalize_reference("`Foo`"), "Foo");
+    assert_eq!(normalize_reference("struct@Foo"), "Foo");
+    assert_eq!(normalize_reference("`fn@do_thing`"), "do_thing");
+    assert_eq!(normalize_reference("do_thing()"), "do_thing");
+    assert_eq!(normalize_reference("`macro@vec`"), "vec");
+    assert_eq!(normalize_reference("std::io::Read"), "std::io::Read");
+}
+
+/// Resolve an intra-doc link reference (e.g. `Foo`, `` `struct@Foo` ``,
+/// `Foo::bar`) into a URL and a title, using the paths recorded in the
+/// rustdoc JSON for this crate and its dependencies.
+///
+/// Returns `None` when the reference can't be matched against any known
+/// item, in which case the caller falls back to leaving it as plain text.
+fn resolve_intra_doc_link(
+    reference: &str,
+    krate: &rustdoc_types::Crate,
+    link_base: &str,
+    package: &cargo_metadata::Package,
+) -> Option<(String, String)> {
+    let raw = normalize_reference(reference);
+
+    let segments = raw
+        .split("::")
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let (id, fragment) = match find_item_summary(krate, &segments) {
+        Some((id, _)) => (id, None),
+        None if segments.len() > 1 => {
+            let (init, [last]) = segments.split_at(segments.len() - 1) else {
+                unreachable!()
+            };
+            let (id, _) = find_item_summary(krate, init)?;
+            (id, Some(*last))
+        }
+        None => return None,
+    };
+
+    let summary = &krate.paths[id];
+    let mut mod_path = summary.path.clone();
+    let item_name = mod_path.pop()?;
+    if !mod_path.is_empty() {
+        mod_path.remove(0); // the leading segment is the crate's own name
+    }
+
+    let base = if summary.crate_id == 0 {
+        // docs.rs keeps the published (possibly hyphenated) name in the first
+        // path segment, followed by the version, but the crate's own module
+        // directory is always the underscored lib name, which rustdoc records
+        // as the leading segment of each of the crate's paths.
+        let lib_name = &summary.path[0];
+        format!("{link_base}/{}/{}/{lib_name}", package.name, package.version)
+    } else {
+        let external = krate.external_crates.get(&summary.crate_id)?;
+        match &external.html_root_url {
+            Some(root) => format!("{}/{}", root.trim_end_matches('/'), external.name),
+            None => format!("{link_base}/{}/latest/{}", external.name, external.name),
+        }
+    };
+
+    let mut url = base;
+    for segment in &mod_path {
+        url.push('/');
+        url.push_str(segment);
+    }
+    url.push('/');
+    match item_kind_prefix(&summary.kind) {
+        Some(prefix) => url.push_str(&format!("{prefix}{item_name}.html")),
+        None => url.push_str(&format!("{item_name}/index.html")),
+    }
+    if let Some(fragment) = fragment {
+        url.push_str("#method.");
+        url.push_str(fragment);
+    }
+
+    Some((url, summary.path.join("::")))
+}
+
+/// Find the [`rustdoc_types::ItemSummary`] whose path ends with `tail`, e.g.
+/// `["io", "Read"]` matches `["std", "io", "Read"]`.
+///
+/// `krate.paths` is a `HashMap`, so iteration order is nondeterministic; when
+/// `tail` is ambiguous (matches more than one item) we deterministically
+/// prefer an item from the crate being documented over an external one,
+/// then the shortest (most likely intended) path, breaking any remaining
+/// tie on the path itself so the same reference always resolves the same
+/// way.
+fn find_item_summary<'a>(
+    krate: &'a rustdoc_types::Crate,
+    tail: &[&str],
+) -> Option<(&'a rustdoc_types::Id, &'a rustdoc_types::ItemSummary)> {
+    if tail.is_empty() {
+        return None;
+    }
+    krate
+        .paths
+        .iter()
+        .filter(|(_, summary)| {
+            summary.path.len() >= tail.len()
+                && summary.path[summary.path.len() - tail.len()..]
+                    .iter()
+                    .zip(tail)
+                    .all(|(have, want)| have == want)
+        })
+        .min_by_key(|(id, summary)| {
+            (
+                summary.crate_id != 0,
+                summary.path.len(),
+                summary.path.join("::"),
+                format!("{id:?}"),
+            )
+        })
+}
+
+/// The filename prefix rustdoc uses for a given item kind, e.g. `struct.` in
+/// `struct.Foo.html`. Modules, which get their own directory, return `None`;
+/// so does every kind not listed here.
+fn item_kind_prefix(kind: &rustdoc_types::ItemKind) -> Option<&'static str> {
+    use rustdoc_types::ItemKind::*;
+    match kind {
+        Struct => Some("struct."),
+        Enum => Some("enum."),
+        Trait => Some("trait."),
+        Function => Some("fn."),
+        Macro => Some("macro."),
+        ProcAttribute => Some("attr."),
+        ProcDerive => Some("derive."),
+        TypeAlias => Some("type."),
+        Constant => Some("constant."),
+        Union => Some("union."),
+        _ => None,
+    }
+}
+
+#[test]
+fn item_kind_prefix_cases() {
+    use rustdoc_types::ItemKind::*;
+    assert_eq!(item_kind_prefix(&Struct), Some("struct."));
+    assert_eq!(item_kind_prefix(&Enum), Some("enum."));
+    assert_eq!(item_kind_prefix(&Trait), Some("trait."));
+    assert_eq!(item_kind_prefix(&Function), Some("fn."));
+    assert_eq!(item_kind_prefix(&Macro), Some("macro."));
+    assert_eq!(item_kind_prefix(&TypeAlias), Some("type."));
+    assert_eq!(item_kind_prefix(&Constant), Some("constant."));
+    assert_eq!(item_kind_prefix(&Union), Some("union."));
+    assert_eq!(item_kind_prefix(&Module), None);
+}
+
 /// Parse args, gracefully exiting the process if parsing fails.
 /// # Panics
 /// - If global logger has already been setup